@@ -8,12 +8,11 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 
 use crate::repo::BlobRepo;
-use anyhow::Error;
-use cloned::cloned;
+use anyhow::Result;
+use async_stream::try_stream;
 use context::CoreContext;
 use filenodes::{FilenodeInfo, FilenodeResult};
-use futures_ext::{BoxStream, FutureExt, StreamExt};
-use futures_old::{future::ok, stream, Future, Stream};
+use futures::{pin_mut, stream::Stream, stream::TryStreamExt};
 use maplit::hashset;
 use mercurial_types::{
     HgFileHistoryEntry, HgFileNodeId, HgParents, MPath, RepoPath, NULL_CSID, NULL_HASH,
@@ -33,46 +32,27 @@ pub enum FilenodesRelatedResult {
 }
 
 /// Checks if one filenode is ancestor of another
-pub fn check_if_related(
+pub async fn check_if_related(
     ctx: CoreContext,
     repo: BlobRepo,
     filenode_a: HgFileNodeId,
     filenode_b: HgFileNodeId,
     path: MPath,
-) -> impl Future<Item = FilenodesRelatedResult, Error = Error> {
-    get_file_history(
-        ctx.clone(),
-        repo.clone(),
-        filenode_a.clone(),
-        path.clone(),
-        None,
-    )
-    .collect()
-    .join(
-        get_file_history(
-            ctx.clone(),
-            repo.clone(),
-            filenode_b.clone(),
-            path.clone(),
-            None,
-        )
-        .collect(),
-    )
-    .map(move |(history_a, history_b)| {
-        if history_a
-            .iter()
-            .any(|entry| entry.filenode() == &filenode_b)
-        {
-            FilenodesRelatedResult::SecondAncestorOfFirst
-        } else if history_b
-            .iter()
-            .any(|entry| entry.filenode() == &filenode_a)
-        {
-            FilenodesRelatedResult::FirstAncestorOfSecond
-        } else {
-            FilenodesRelatedResult::Unrelated
-        }
-    })
+) -> Result<FilenodesRelatedResult> {
+    let history_a = get_file_history(ctx.clone(), repo.clone(), filenode_a, path.clone(), None)
+        .try_collect::<Vec<_>>()
+        .await?;
+    let history_b = get_file_history(ctx, repo, filenode_b, path, None)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    if history_a.iter().any(|entry| entry.filenode() == &filenode_b) {
+        Ok(FilenodesRelatedResult::SecondAncestorOfFirst)
+    } else if history_b.iter().any(|entry| entry.filenode() == &filenode_a) {
+        Ok(FilenodesRelatedResult::FirstAncestorOfSecond)
+    } else {
+        Ok(FilenodesRelatedResult::Unrelated)
+    }
 }
 
 /// Get the history of the file corresponding to the given filenode and path.
@@ -82,28 +62,32 @@ pub fn get_file_history(
     filenode: HgFileNodeId,
     path: MPath,
     max_length: Option<u32>,
-) -> impl Stream<Item = HgFileHistoryEntry, Error = Error> {
-    prefetch_history(ctx.clone(), repo.clone(), path.clone())
-        .map(move |prefetched| {
-            get_file_history_using_prefetched(ctx, repo, filenode, path, max_length, prefetched)
-        })
-        .flatten_stream()
+) -> impl Stream<Item = Result<HgFileHistoryEntry>> {
+    try_stream! {
+        let prefetched = prefetch_history(ctx.clone(), repo.clone(), path.clone()).await?;
+        let history =
+            get_file_history_using_prefetched(ctx, repo, filenode, path, max_length, prefetched);
+        pin_mut!(history);
+        while let Some(entry) = history.try_next().await? {
+            yield entry;
+        }
+    }
 }
 
 /// Prefetch and cache filenode information. Performing these fetches in bulk upfront
 /// prevents an excessive number of DB roundtrips when constructing file history.
-fn prefetch_history(
+async fn prefetch_history(
     ctx: CoreContext,
     repo: BlobRepo,
     path: MPath,
-) -> impl Future<Item = HashMap<HgFileNodeId, FilenodeInfo>, Error = Error> {
-    repo.get_all_filenodes_maybe_stale(ctx, RepoPath::FilePath(path))
-        .map(|filenodes| {
-            filenodes
-                .into_iter()
-                .map(|filenode| (filenode.filenode, filenode))
-                .collect()
-        })
+) -> Result<HashMap<HgFileNodeId, FilenodeInfo>> {
+    let filenodes = repo
+        .get_all_filenodes_maybe_stale(ctx, RepoPath::FilePath(path))
+        .await?;
+    Ok(filenodes
+        .into_iter()
+        .map(|filenode| (filenode.filenode, filenode))
+        .collect())
 }
 
 /// Get the history of the file at the specified path, using the given
@@ -118,93 +102,172 @@ fn get_file_history_using_prefetched(
     path: MPath,
     max_length: Option<u32>,
     prefetched_history: HashMap<HgFileNodeId, FilenodeInfo>,
-) -> BoxStream<HgFileHistoryEntry, Error> {
-    if startnode == HgFileNodeId::new(NULL_HASH) {
-        return stream::empty().boxify();
-    }
+) -> impl Stream<Item = Result<HgFileHistoryEntry>> {
+    try_stream! {
+        if startnode == HgFileNodeId::new(NULL_HASH) {
+            return;
+        }
+
+        let mut nodes = VecDeque::new();
+        nodes.push_back((path.clone(), startnode));
+        let mut seen_nodes: HashSet<HgFileNodeId> = hashset! {startnode};
+
+        // Per-path prefetched history. The initial path is seeded with the
+        // prefetched map the caller handed us; further paths (reached by
+        // following a rename) are populated lazily the first time they are
+        // visited.
+        let mut prefetched: HashMap<MPath, HashMap<HgFileNodeId, FilenodeInfo>> = HashMap::new();
+        prefetched.insert(path, prefetched_history);
 
-    let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes = hashset! {startnode};
-    let path = RepoPath::FilePath(path);
-
-    // TODO: There is probably another thundering herd problem here. If we change a file twice,
-    // then the original cached value will be reused, and we'll keep going back to getting the
-    // filenode individualy (perhaps not the end of the world?).
-    stream::unfold(
-        (startstate, seen_nodes, 0),
-        move |(mut nodes, mut seen_nodes, length): (
-            VecDeque<HgFileNodeId>,
-            HashSet<HgFileNodeId>,
-            u32,
-        )| {
-            match max_length {
-                Some(max_length) if length >= max_length => return None,
-                _ => {}
+        let mut length = 0;
+        while let Some((path, node)) = nodes.pop_front() {
+            if let Some(max_length) = max_length {
+                if length >= max_length {
+                    break;
+                }
             }
 
-            let node = nodes.pop_front()?;
+            if !prefetched.contains_key(&path) {
+                let history = prefetch_history(ctx.clone(), repo.clone(), path.clone()).await?;
+                prefetched.insert(path.clone(), history);
+            }
 
-            let filenode_fut = if let Some(filenode) = prefetched_history.get(&node) {
-                ok(filenode.clone()).left_future()
-            } else {
-                get_maybe_missing_filenode(ctx.clone(), repo.clone(), path.clone(), node)
-                    .right_future()
+            let repo_path = RepoPath::FilePath(path.clone());
+            let filenode = match prefetched.get(&path).and_then(|m| m.get(&node)) {
+                Some(filenode) => filenode.clone(),
+                None => {
+                    get_maybe_missing_filenode(ctx.clone(), repo.clone(), repo_path.clone(), node)
+                        .await?
+                }
             };
 
-            cloned!(path);
-
-            let history = filenode_fut.and_then(move |filenode| {
-                let p1 = filenode.p1.map(|p| p.into_nodehash());
-                let p2 = filenode.p2.map(|p| p.into_nodehash());
-                let parents = HgParents::new(p1, p2);
-
-                let linknode = filenode.linknode;
-
-                let copyfrom = match filenode.copyfrom {
-                    Some((RepoPath::FilePath(frompath), node)) => Some((frompath, node)),
-                    Some((frompath, _)) => {
-                        return Err(ErrorKind::InconsistentCopyInfo(path, frompath).into());
-                    }
-                    None => None,
-                };
-
-                let entry = HgFileHistoryEntry::new(node, parents, linknode, copyfrom);
-
-                nodes.extend(
-                    parents
-                        .into_iter()
-                        .map(HgFileNodeId::new)
-                        .filter(|p| seen_nodes.insert(*p)),
-                );
-                Ok((entry, (nodes, seen_nodes, length + 1)))
-            });
-
-            Some(history)
-        },
-    )
-    .boxify()
+            let p1 = filenode.p1.map(|p| p.into_nodehash());
+            let p2 = filenode.p2.map(|p| p.into_nodehash());
+            let parents = HgParents::new(p1, p2);
+            let linknode = filenode.linknode;
+
+            // Track the renamed path so the `InconsistentCopyInfo` check still
+            // fires for directory copies.
+            let copyfrom = match filenode.copyfrom {
+                Some((RepoPath::FilePath(frompath), fromnode)) => Some((frompath, fromnode)),
+                Some((frompath, _)) => {
+                    return Err(ErrorKind::InconsistentCopyInfo(repo_path, frompath).into());
+                }
+                None => None,
+            };
+
+            let entry = HgFileHistoryEntry::new(node, parents, linknode, copyfrom.clone());
+
+            // Continue walking the file's parents under the current path.
+            nodes.extend(
+                parents
+                    .into_iter()
+                    .map(HgFileNodeId::new)
+                    .filter(|p| seen_nodes.insert(*p))
+                    .map(|p| (path.clone(), p)),
+            );
+
+            // Continue the history across a rename so subsequent entries
+            // describe the file under its old name.
+            if let Some((frompath, fromnode)) = copyfrom {
+                if seen_nodes.insert(fromnode) {
+                    nodes.push_back((frompath, fromnode));
+                }
+            }
+
+            length += 1;
+            yield entry;
+        }
+    }
 }
 
-fn get_maybe_missing_filenode(
+async fn get_maybe_missing_filenode(
     ctx: CoreContext,
     repo: BlobRepo,
     path: RepoPath,
     node: HgFileNodeId,
-) -> impl Future<Item = FilenodeInfo, Error = Error> {
-    repo.get_filenode_opt(ctx.clone(), &path, node).and_then({
-        cloned!(repo, ctx, path, node);
-        move |filenode_res| match filenode_res {
-            FilenodeResult::Present(Some(filenode)) => ok(filenode).left_future(),
-            FilenodeResult::Present(None) | FilenodeResult::Disabled => {
-                // The filenode couldn't be found.  This may be because it is a
-                // draft node, which doesn't get stored in the database or because
-                // filenodes were intentionally disabled.  Attempt
-                // to reconstruct the filenode from the envelope.  Use `NULL_CSID`
-                // to indicate a draft or missing linknode.
-                repo.get_filenode_from_envelope(ctx, &path, node, NULL_CSID)
-                    .right_future()
-            }
+) -> Result<FilenodeInfo> {
+    let filenode_res = repo.get_filenode_opt(ctx.clone(), &path, node).await?;
+    match filenode_res {
+        FilenodeResult::Present(Some(filenode)) => Ok(filenode),
+        FilenodeResult::Present(None) | FilenodeResult::Disabled => {
+            // The filenode couldn't be found.  This may be because it is a
+            // draft node, which doesn't get stored in the database or because
+            // filenodes were intentionally disabled.  Attempt
+            // to reconstruct the filenode from the envelope.  Use `NULL_CSID`
+            // to indicate a draft or missing linknode.
+            repo.get_filenode_from_envelope(ctx, &path, node, NULL_CSID)
+                .await
         }
-    })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use blobrepo_factory::new_memblob_empty;
+    use blobstore::Loadable;
+    use fbinit::FacebookInit;
+    use mononoke_types::ChangesetId;
+    use tests_utils::CreateCommitContext;
+
+    /// Resolve the `HgFileNodeId` of `path` as of the given bonsai changeset.
+    async fn file_node(
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+        cs_id: ChangesetId,
+        path: &MPath,
+    ) -> HgFileNodeId {
+        let hg_cs_id = repo
+            .get_hg_from_bonsai_changeset(ctx.clone(), cs_id)
+            .await
+            .unwrap();
+        let hg_cs = hg_cs_id.load(ctx.clone(), repo.blobstore()).await.unwrap();
+        let entry = hg_cs
+            .manifestid()
+            .find_entry(ctx.clone(), repo.get_blobstore(), Some(path.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        entry.into_leaf().unwrap().1
+    }
+
+    // A file created by copying another keeps walking under the old name, and
+    // the walk terminates once the copy source has been visited.
+    #[fbinit::test]
+    async fn follows_copy_and_terminates(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = new_memblob_empty(None)?;
+
+        let foo = MPath::new("foo")?;
+        let bar = MPath::new("bar")?;
+
+        // `foo` exists, then `bar` is created as a copy of `foo`.
+        let c1 = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("foo", "foo contents")
+            .commit()
+            .await?;
+        let c2 = CreateCommitContext::new(&ctx, &repo, vec![c1])
+            .add_file_with_copy_info("bar", "foo contents", (c1, "foo"))
+            .commit()
+            .await?;
+
+        let bar_node = file_node(&ctx, &repo, c2, &bar).await;
+        let foo_node = file_node(&ctx, &repo, c1, &foo).await;
+
+        let history = get_file_history(ctx.clone(), repo.clone(), bar_node, bar.clone(), None)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        // The first entry is `bar` and records the copy from `foo`.
+        assert_eq!(history[0].filenode(), &bar_node);
+        assert!(history[0].copyfrom().is_some());
+
+        // The stream continued under the old name and reached `foo`'s filenode,
+        // and it terminated rather than looping forever.
+        assert!(history.iter().any(|entry| entry.filenode() == &foo_node));
+
+        Ok(())
+    }
 }
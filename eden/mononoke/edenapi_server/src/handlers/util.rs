@@ -6,30 +6,89 @@
  */
 
 use anyhow::anyhow;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::stream::TryStreamExt;
 use gotham::state::{FromState, State};
+use http::header::ACCEPT;
 use http::HeaderMap;
 use hyper::Body;
 use mime::Mime;
 use once_cell::sync::Lazy;
 
-use gotham_ext::{body_ext::BodyExt, error::HttpError};
+use gotham_ext::error::HttpError;
 use mononoke_api::hg::HgRepoContext;
 
 use crate::context::ServerContext;
-use crate::middleware::RequestContext;
+use crate::middleware::{thread_identity_into_context, RequestContext};
 
 static CBOR_MIME: Lazy<Mime> = Lazy::new(|| "application/cbor".parse().unwrap());
+static JSON_MIME: Lazy<Mime> = Lazy::new(|| "application/json".parse().unwrap());
+
+/// Default cap on a request body before the handler refuses to buffer more.
+const MAX_REQUEST_BODY_SIZE: usize = 64 * 1024 * 1024;
 
 pub fn cbor_mime() -> Mime {
     CBOR_MIME.clone()
 }
 
+/// Representation a handler will serialize its response as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Cbor,
+    Json,
+}
+
+impl ResponseFormat {
+    pub fn mime(self) -> Mime {
+        match self {
+            ResponseFormat::Cbor => CBOR_MIME.clone(),
+            ResponseFormat::Json => JSON_MIME.clone(),
+        }
+    }
+}
+
+/// Pick a response representation from the request's `Accept` header.
+///
+/// CBOR is the native wire format, so we only switch to JSON when the client
+/// explicitly asks for `application/json` without also accepting CBOR. This
+/// lets browsers and debugging tools read the same endpoints the production
+/// clients use while keeping CBOR the default.
+pub fn get_response_format(state: &State) -> ResponseFormat {
+    let accept = HeaderMap::try_borrow_from(state)
+        .and_then(|headers| headers.get(ACCEPT))
+        .and_then(|value| value.to_str().ok());
+    choose_format(accept)
+}
+
+/// Pure negotiation over an `Accept` header value: JSON only when the client
+/// asked for it without also accepting CBOR, otherwise the CBOR default.
+fn choose_format(accept: Option<&str>) -> ResponseFormat {
+    match accept {
+        Some(accept) => {
+            let wants_cbor = accept.contains(CBOR_MIME.as_ref());
+            let wants_json = accept.contains(JSON_MIME.as_ref());
+            if wants_json && !wants_cbor {
+                ResponseFormat::Json
+            } else {
+                ResponseFormat::Cbor
+            }
+        }
+        None => ResponseFormat::Cbor,
+    }
+}
+
 pub async fn get_repo(
     sctx: &ServerContext,
-    rctx: &RequestContext,
+    state: &mut State,
     name: impl AsRef<str>,
 ) -> Result<HgRepoContext, HttpError> {
+    // Fold the caller identity resolved by `ClientIdentityMiddleware` into the
+    // request's `CoreContext` before we hand it to the API, so per-request ACL
+    // checks and audit logging see the real end-user rather than an
+    // unattributed connection.
+    thread_identity_into_context(state);
+
+    let rctx = RequestContext::borrow_from(state);
     let name = name.as_ref();
     sctx.mononoke_api()
         .repo(rctx.core_context().clone(), name)
@@ -40,10 +99,70 @@ pub async fn get_repo(
 }
 
 pub async fn get_request_body(state: &mut State) -> Result<Bytes, HttpError> {
+    get_request_body_with_limit(state, MAX_REQUEST_BODY_SIZE).await
+}
+
+/// Stream the request body into memory, rejecting it as soon as it exceeds
+/// `max_size` instead of buffering an unbounded upload. The cap is enforced
+/// while concatenating so an oversized body never gets fully materialized.
+pub async fn get_request_body_with_limit(
+    state: &mut State,
+    max_size: usize,
+) -> Result<Bytes, HttpError> {
     let body = Body::take_from(state);
-    let headers = HeaderMap::try_borrow_from(state);
-    body.try_concat_body_opt(headers)
-        .map_err(HttpError::e400)?
-        .await
-        .map_err(HttpError::e400)
+    concat_body_with_limit(body, max_size).await
+}
+
+/// Stream `body` into memory, failing with `HttpError::e400` the moment it
+/// would exceed `max_size` so an oversized upload is never fully buffered.
+async fn concat_body_with_limit(mut body: Body, max_size: usize) -> Result<Bytes, HttpError> {
+    let mut buf = BytesMut::new();
+
+    while let Some(chunk) = body.try_next().await.map_err(HttpError::e400)? {
+        if buf.len() + chunk.len() > max_size {
+            return Err(HttpError::e400(anyhow!(
+                "request body exceeds maximum allowed size of {} bytes",
+                max_size
+            )));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiate_defaults_to_cbor_when_accept_absent() {
+        assert_eq!(choose_format(None), ResponseFormat::Cbor);
+    }
+
+    #[test]
+    fn negotiate_json_when_explicitly_requested() {
+        assert_eq!(choose_format(Some("application/json")), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn negotiate_prefers_cbor_when_both_are_accepted() {
+        assert_eq!(
+            choose_format(Some("application/json, application/cbor")),
+            ResponseFormat::Cbor
+        );
+    }
+
+    #[tokio::test]
+    async fn body_just_under_limit_succeeds() {
+        let body = Body::from(vec![b'x'; 8]);
+        let bytes = concat_body_with_limit(body, 9).await.unwrap();
+        assert_eq!(bytes.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn body_one_byte_over_limit_is_rejected() {
+        let body = Body::from(vec![b'x'; 10]);
+        assert!(concat_body_with_limit(body, 9).await.is_err());
+    }
 }
@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::net::IpAddr;
+
+use gotham::state::{client_addr, FromState, State};
+use gotham_derive::StateData;
+use hyper::header::HeaderMap;
+use hyper::{Body, Response};
+use permission_checker::{MononokeIdentity, MononokeIdentitySet};
+
+use gotham_ext::middleware::Middleware;
+
+use super::RequestContext;
+
+/// Header used by a trusted proxy to forward the identity of the real
+/// end-user sitting behind it.
+const ENCODED_CLIENT_IDENTITY: &str = "x-fb-validated-client-encoded-identity";
+
+/// Per-request view of who the caller is. Populated by
+/// [`ClientIdentityMiddleware`] and read when constructing the `CoreContext`
+/// that [`crate::handlers::util::get_repo`] later hands to the API.
+#[derive(Clone, StateData, Default)]
+pub struct ClientIdentity {
+    address: Option<IpAddr>,
+    identities: Option<MononokeIdentitySet>,
+}
+
+impl ClientIdentity {
+    pub fn address(&self) -> &Option<IpAddr> {
+        &self.address
+    }
+
+    pub fn identities(&self) -> &Option<MononokeIdentitySet> {
+        &self.identities
+    }
+}
+
+/// Socket data stashed at accept time: the peer certificate's identities as
+/// seen during the TLS handshake. A plaintext listener leaves this empty.
+#[derive(Clone, StateData, Default)]
+pub struct TlsCertificateIdentities {
+    pub identities: MononokeIdentitySet,
+}
+
+/// Resolves the caller's identity on every request.
+///
+/// The peer certificate identities come from the accepted socket. If the peer
+/// is one of the configured trusted proxies we additionally honor a
+/// forwarded-identity header, so the identity threaded into the `CoreContext`
+/// is the real end-user rather than the proxy. Untrusted peers only ever get
+/// their own certificate identities and any forwarded header is ignored.
+#[derive(Clone)]
+pub struct ClientIdentityMiddleware {
+    trusted_proxies: MononokeIdentitySet,
+}
+
+impl ClientIdentityMiddleware {
+    pub fn new(trusted_proxies: MononokeIdentitySet) -> Self {
+        Self { trusted_proxies }
+    }
+
+    fn extract_cert_identities(state: &mut State) -> MononokeIdentitySet {
+        TlsCertificateIdentities::try_borrow_from(state)
+            .map(|tls| tls.identities.clone())
+            .unwrap_or_default()
+    }
+
+    fn is_trusted_proxy(&self, identities: &MononokeIdentitySet) -> bool {
+        !self.trusted_proxies.is_empty()
+            && identities.iter().any(|id| self.trusted_proxies.contains(id))
+    }
+
+    fn forwarded_identities(state: &State) -> Option<MononokeIdentitySet> {
+        let headers = HeaderMap::try_borrow_from(state)?;
+        let encoded = headers.get(ENCODED_CLIENT_IDENTITY)?.to_str().ok()?;
+        MononokeIdentity::try_from_json_encoded(encoded).ok()
+    }
+}
+
+impl Middleware for ClientIdentityMiddleware {
+    fn inbound(&self, state: &mut State) {
+        let cert_identities = Self::extract_cert_identities(state);
+        let address = client_addr(state).map(|addr| addr.ip());
+
+        // A trusted proxy may speak for the end-user; everyone else speaks only
+        // for themselves.
+        let identities = if self.is_trusted_proxy(&cert_identities) {
+            Self::forwarded_identities(state).unwrap_or(cert_identities)
+        } else {
+            cert_identities
+        };
+
+        state.put(ClientIdentity {
+            address,
+            identities: Some(identities),
+        });
+    }
+
+    fn outbound(&self, _state: &mut State, _response: &mut Response<Body>) {}
+}
+
+/// Fold the resolved [`ClientIdentity`] into the request's `RequestContext` so
+/// the `CoreContext` it hands to the API carries the real caller for ACL
+/// enforcement and audit attribution. A no-op if either piece of state is
+/// absent (e.g. `ClientIdentityMiddleware` did not run for this request). On a
+/// plaintext listener the middleware resolves an empty identity set, which is
+/// set like any other.
+pub fn thread_identity_into_context(state: &mut State) {
+    let identities = ClientIdentity::try_borrow_from(state)
+        .and_then(|identity| identity.identities().clone());
+
+    if let Some(identities) = identities {
+        if let Some(rctx) = RequestContext::try_borrow_mut_from(state) {
+            rctx.set_identities(identities);
+        }
+    }
+}
@@ -0,0 +1,15 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+mod client_identity;
+mod request_context;
+
+pub use client_identity::{
+    thread_identity_into_context, ClientIdentity, ClientIdentityMiddleware,
+    TlsCertificateIdentities,
+};
+pub use request_context::RequestContext;
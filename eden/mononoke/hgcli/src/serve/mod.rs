@@ -8,7 +8,8 @@
 use std::env::var;
 use std::io as std_io;
 use std::net::{IpAddr, SocketAddr};
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, format_err, Error, Result};
 use bytes::Bytes;
@@ -21,6 +22,13 @@ use slog::{debug, error, o, Drain, Logger};
 use dns_lookup::lookup_addr;
 use libc::c_ulong;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use rand::Rng;
+use tokio::timer::Delay;
+use permission_checker::{MononokeIdentity, MononokeIdentitySet};
+use proxy_protocol::{
+    version2::{ProxyAddresses, ProxyCommand, ProxyTransportProtocol},
+    ProxyHeader,
+};
 use tokio_io::codec::{FramedRead, FramedWrite};
 use tokio_io::AsyncRead;
 use tokio_openssl::{SslConnectorExt, SslStream};
@@ -29,7 +37,7 @@ use users::get_current_username;
 use tokio::net::TcpStream;
 use tokio::util::FutureExt as TokioFutureExt;
 
-use clap::ArgMatches;
+use clap::{App, Arg, ArgMatches};
 
 use failure_ext::{err_downcast_ref, SlogKVError};
 use futures::compat::Future01CompatExt;
@@ -43,12 +51,114 @@ use sshrelay::{
 };
 
 mod fdio;
+mod quic;
+
+/// Wire transport used to reach the Mononoke server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transport {
+    /// The default: ssh framing multiplexed over a single TLS-over-TCP stream.
+    Tcp,
+    /// ssh framing spread across independent QUIC streams to avoid
+    /// head-of-line blocking between stdout and stderr.
+    Quic,
+}
+
+impl FromStr for Transport {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Transport::Tcp),
+            "quic" => Ok(Transport::Quic),
+            other => bail!("unknown transport '{}', expected 'tcp' or 'quic'", other),
+        }
+    }
+}
 
 const X509_R_CERT_ALREADY_IN_HASH_TABLE: c_ulong = 185057381;
 
 // Wait for up to 1sec to let Scuba flush its data to the server.
 const SCUBA_TIMEOUT: Duration = Duration::from_millis(1000);
 
+// Protocol name advertised via ALPN by default so a Mononoke server can
+// dispatch hgcli vs. HTTP/2 from the TLS handshake instead of sniffing the
+// socket.
+const DEFAULT_ALPN_PROTOCOL: &str = "hgcli-0";
+
+// Per-attempt timeout covering TCP connect + TLS handshake.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+// Number of extra dials after the first one before giving up.
+const DEFAULT_CONNECT_RETRIES: u32 = 0;
+// Backoff bounds for the retry loop.
+const INITIAL_CONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+// Hard cap on the total time spent across all connect attempts.
+const DEFAULT_CONNECT_DEADLINE: Duration = Duration::from_secs(60);
+
+/// Encode a list of ALPN protocol names into the wire format expected by
+/// OpenSSL: each name prefixed by a single length byte.
+fn encode_alpn_protos(protocols: &[String]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for protocol in protocols {
+        encoded.push(protocol.len() as u8);
+        encoded.extend_from_slice(protocol.as_bytes());
+    }
+    encoded
+}
+
+/// Register the flags the `serve`/`stdio` subcommand understands on top of the
+/// ones defined by the caller. The top-level subcommand builder threads its
+/// `App` through here so the transport/security knobs live next to the code
+/// that reads them.
+pub fn add_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+    subcommand.arg(
+        Arg::with_name("alpn-protocol")
+            .long("alpn-protocol")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("ALPN protocol name(s) to advertise during the TLS handshake"),
+    )
+    .arg(
+        Arg::with_name("transport")
+            .long("transport")
+            .takes_value(true)
+            .possible_values(&["tcp", "quic"])
+            .help("transport used to reach Mononoke (defaults to tcp)"),
+    )
+    .arg(
+        Arg::with_name("proxy-protocol-v2")
+            .long("proxy-protocol-v2")
+            .help("prepend a PROXY protocol v2 header conveying the originating client"),
+    )
+    .arg(
+        Arg::with_name("service-identity")
+            .long("service-identity")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("require the server certificate to present this service identity (repeatable; tcp transport only)"),
+    )
+    .arg(
+        Arg::with_name("connect-retries")
+            .long("connect-retries")
+            .takes_value(true)
+            .help("number of extra connection attempts before giving up"),
+    )
+    .arg(
+        Arg::with_name("connect-timeout")
+            .long("connect-timeout")
+            .takes_value(true)
+            .help("per-attempt connect + TLS handshake timeout, in seconds"),
+    )
+    .arg(
+        Arg::with_name("connect-deadline")
+            .long("connect-deadline")
+            .takes_value(true)
+            .help("hard cap on total time spent across all connect attempts, in seconds"),
+    )
+}
+
 pub async fn cmd(
     fb: FacebookInit,
     main: &ArgMatches<'_>,
@@ -81,6 +191,39 @@ pub async fn cmd(
             let is_remote_proxy = main.is_present("remote-proxy");
             let scuba_table = main.value_of("scuba-table");
             let mock_username = sub.value_of("mock-username");
+            let alpn_protocols = sub
+                .values_of("alpn-protocol")
+                .map(|values| values.map(|v| v.to_string()).collect())
+                .unwrap_or_else(|| vec![DEFAULT_ALPN_PROTOCOL.to_string()]);
+            let send_proxy_header = sub.is_present("proxy-protocol-v2");
+            let transport = sub
+                .value_of("transport")
+                .map(Transport::from_str)
+                .transpose()?
+                .unwrap_or(Transport::Tcp);
+            let connect_retries = sub
+                .value_of("connect-retries")
+                .map(|v| v.parse())
+                .transpose()?
+                .unwrap_or(DEFAULT_CONNECT_RETRIES);
+            let connect_timeout = sub
+                .value_of("connect-timeout")
+                .map(|v| v.parse().map(Duration::from_secs))
+                .transpose()?
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+            let connect_deadline = sub
+                .value_of("connect-deadline")
+                .map(|v| v.parse().map(Duration::from_secs))
+                .transpose()?
+                .unwrap_or(DEFAULT_CONNECT_DEADLINE);
+            let service_identities = match sub.values_of("service-identity") {
+                Some(values) => Some(
+                    values
+                        .map(MononokeIdentity::from_str)
+                        .collect::<Result<MononokeIdentitySet>>()?,
+                ),
+                None => None,
+            };
 
             return StdioRelay {
                 fb,
@@ -97,6 +240,13 @@ pub async fn cmd(
                 mock_username,
                 show_session_output,
                 priority,
+                alpn_protocols,
+                send_proxy_header,
+                transport,
+                connect_retries,
+                connect_timeout,
+                connect_deadline,
+                service_identities,
             }
             .run()
             .await;
@@ -121,6 +271,23 @@ struct StdioRelay<'a> {
     mock_username: Option<&'a str>,
     show_session_output: bool,
     priority: Priority,
+    alpn_protocols: Vec<String>,
+    // When true, prepend a PROXY protocol v2 header to the TLS stream so a
+    // proxy-aware Mononoke learns the originating client directly instead of
+    // relying on the reverse-DNS hostname carried in the Preamble.
+    send_proxy_header: bool,
+    // Transport used to reach Mononoke: TLS-over-TCP by default, QUIC opt-in.
+    transport: Transport,
+    // Number of extra connection attempts beyond the first.
+    connect_retries: u32,
+    // Per-attempt timeout for TCP connect + TLS handshake.
+    connect_timeout: Duration,
+    // Hard cap on the total time spent across all connect attempts.
+    connect_deadline: Duration,
+    // When set, the peer certificate must present every one of these service
+    // identities in addition to matching `ssl_common_name`. Only checked on
+    // the TCP transport; the QUIC transport does not verify them.
+    service_identities: Option<MononokeIdentitySet>,
 }
 
 impl<'a> StdioRelay<'a> {
@@ -197,7 +364,7 @@ impl<'a> StdioRelay<'a> {
 
         scuba_logger.log_with_msg("Hgcli proxy - Connected", None);
 
-        let (stats, result) = self.internal_run(stdio).timed().await;
+        let (stats, result) = self.internal_run(stdio, &mut scuba_logger).timed().await;
         scuba_logger.add_future_stats(&stats);
         match result {
             Ok(_) => scuba_logger.log_with_msg("Hgcli proxy - Success", None),
@@ -210,63 +377,223 @@ impl<'a> StdioRelay<'a> {
         Ok(())
     }
 
-    async fn establish_connection(&self) -> Result<SslStream<TcpStream>, Error> {
-        let path = self.path.to_owned();
-        let ssl_common_name = self.ssl_common_name.to_owned();
+    /// Build the OpenSSL connector for one dial. Rebuilt per attempt since
+    /// `connect_async` consumes it.
+    fn build_ssl_connector(&self, alpn_protocols: &[String]) -> Result<SslConnector, Error> {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
 
-        let connector = {
-            let mut connector = SslConnector::builder(SslMethod::tls())?;
+        if self.insecure {
+            connector.set_verify(SslVerifyMode::NONE);
+        }
 
-            if self.insecure {
-                connector.set_verify(SslVerifyMode::NONE);
-            }
+        // Advertise the hgcli protocol(s) so the server can dispatch on the
+        // ALPN selection rather than peeking at the byte stream.
+        if !alpn_protocols.is_empty() {
+            connector.set_alpn_protos(&encode_alpn_protos(alpn_protocols))?;
+        }
 
-            let pkcs12 = build_identity(self.cert.to_owned(), self.private_key.to_owned())?;
-            connector.set_certificate(&pkcs12.cert)?;
-            connector.set_private_key(&pkcs12.pkey)?;
-
-            // add root certificate
-
-            connector
-                .cert_store_mut()
-                .add_cert(read_x509(self.ca_pem)?)
-                .or_else(|err| {
-                    let mut failed = true;
-                    {
-                        let errors = err.errors();
-                        if errors.len() == 1 {
-                            if errors[0].code() == X509_R_CERT_ALREADY_IN_HASH_TABLE {
-                                // Do not fail if certificate has already been added since it's
-                                // not really an error
-                                failed = false;
-                            }
+        let pkcs12 = build_identity(self.cert.to_owned(), self.private_key.to_owned())?;
+        connector.set_certificate(&pkcs12.cert)?;
+        connector.set_private_key(&pkcs12.pkey)?;
+
+        // add root certificate
+
+        connector
+            .cert_store_mut()
+            .add_cert(read_x509(self.ca_pem)?)
+            .or_else(|err| {
+                let mut failed = true;
+                {
+                    let errors = err.errors();
+                    if errors.len() == 1 {
+                        if errors[0].code() == X509_R_CERT_ALREADY_IN_HASH_TABLE {
+                            // Do not fail if certificate has already been added since it's
+                            // not really an error
+                            failed = false;
                         }
                     }
-                    if failed {
-                        let err: Error = err.into();
-                        Err(err)
-                    } else {
-                        Ok(())
-                    }
-                })?;
+                }
+                if failed {
+                    let err: Error = err.into();
+                    Err(err)
+                } else {
+                    Ok(())
+                }
+            })?;
+
+        Ok(connector.build())
+    }
+
+    async fn establish_connection(
+        &self,
+        scuba_logger: &mut ScubaSampleBuilder,
+    ) -> Result<SslStream<TcpStream>, Error> {
+        let path = self.path.to_owned();
+        let ssl_common_name = self.ssl_common_name.to_owned();
+        let alpn_protocols = self.alpn_protocols.clone();
+
+        // Dial with bounded retries: a VIP briefly draining a backend shows up
+        // as a connect refusal or a handshake timeout, and a single attempt
+        // turns that transient blip into a user-visible failure. Every attempt
+        // dials the `ip:port` endpoint afresh (no name resolution is done) and
+        // its latency/outcome is logged so flaky VIPs are observable. The whole loop is capped by `connect_deadline`
+        // so hgcli never hangs indefinitely.
+        let overall_deadline = Instant::now() + self.connect_deadline;
+        let mut backoff = INITIAL_CONNECT_BACKOFF;
+        let mut attempt = 0u32;
+        let stream = loop {
+            attempt += 1;
+
+            let connector = self.build_ssl_connector(&alpn_protocols)?;
+            let path = path.clone();
+            let addr: SocketAddr = path.parse()?;
+            let ssl_common_name = ssl_common_name.clone();
+            // Bound each attempt by the per-attempt timeout, but never let it
+            // run past the overall deadline, so a single hung TCP connect (only
+            // the OS SYN timeout otherwise) can't blow past `connect_deadline`.
+            let attempt_timeout = std::cmp::min(
+                self.connect_timeout,
+                overall_deadline.saturating_duration_since(Instant::now()),
+            );
+
+            let (stats, result) = async move {
+                TcpStream::connect(&addr)
+                    .map_err(move |err| {
+                        format_err!("connecting to Mononoke {} socket '{}' failed", path, err)
+                    })
+                    .and_then(move |socket| {
+                        connector
+                            .connect_async(&ssl_common_name, socket)
+                            .map_err(|err| format_err!("async connect error {}", err))
+                    })
+                    // Timeout covers TCP connect *and* the TLS handshake.
+                    .timeout(attempt_timeout)
+                    .map_err(|err| format_err!("connect attempt failed or timed out: {}", err))
+                    .compat()
+                    .await
+            }
+            .timed()
+            .await;
+
+            scuba_logger.add("connect_attempt", attempt);
+            scuba_logger.add("connect_latency_ms", stats.completion_time.as_millis() as u64);
 
-            connector.build()
+            match result {
+                Ok(stream) => {
+                    scuba_logger.add("connect_outcome", "success");
+                    break stream;
+                }
+                Err(err) => {
+                    scuba_logger.add("connect_outcome", "failure");
+                    // Exponential backoff with full jitter to avoid retry
+                    // stampedes against a recovering VIP: wait a random time
+                    // up to the current backoff, which doubles per attempt up
+                    // to MAX_CONNECT_BACKOFF.
+                    let jitter = rand::thread_rng().gen_range(0, backoff.as_millis() as u64 + 1);
+                    let next_attempt_at = Instant::now() + Duration::from_millis(jitter);
+                    if attempt > self.connect_retries || next_attempt_at >= overall_deadline {
+                        return Err(err);
+                    }
+                    Delay::new(next_attempt_at)
+                        .compat()
+                        .await
+                        .map_err(|err| format_err!("connect backoff timer error: {}", err))?;
+                    backoff = std::cmp::min(backoff * 2, MAX_CONNECT_BACKOFF);
+                }
+            }
         };
 
-        let addr: SocketAddr = path.parse()?;
-        TcpStream::connect(&addr)
-            .map_err(|err| format_err!("connecting to Mononoke {} socket '{}' failed", path, err))
-            .and_then(move |socket| {
-                let async_connector = connector
-                    .connect_async(&ssl_common_name, socket)
-                    .timeout(Duration::from_secs(15));
-                async_connector.map_err(|err| format_err!("async connect error {}", err))
+        // Record whether the server honoured our ALPN offer. A server that
+        // predates ALPN dispatch selects nothing and we carry on as before.
+        if !alpn_protocols.is_empty() {
+            match stream.get_ref().ssl().selected_alpn_protocol() {
+                Some(selected) => {
+                    scuba_logger.add("alpn_selected", String::from_utf8_lossy(selected).into_owned());
+                }
+                None => {
+                    scuba_logger.add("alpn_selected", "");
+                }
+            }
+        }
+
+        // Service-identity pinning: a certificate can be signed by our CA and
+        // carry the expected common name yet still belong to the wrong service
+        // (e.g. a misrouted host). Require the presented identities to be a
+        // superset of the ones the caller demanded before trusting the peer.
+        if let Some(expected) = self.service_identities.as_ref() {
+            let presented = match stream.get_ref().ssl().peer_certificate() {
+                Some(cert) => MononokeIdentity::try_from_x509(&cert)?,
+                None => bail!("Mononoke server presented no certificate to verify identity"),
+            };
+
+            let missing: MononokeIdentitySet = expected
+                .iter()
+                .filter(|identity| !presented.contains(identity))
+                .cloned()
+                .collect();
+
+            if !missing.is_empty() {
+                scuba_logger.add("rejected_service_identities", format!("{:?}", missing));
+                bail!(
+                    "Mononoke server identity {:?} does not include required {:?}",
+                    presented,
+                    missing
+                );
+            }
+        }
+
+        Ok(stream)
+    }
+
+    /// Encode a PROXY protocol v2 header describing the originating client.
+    ///
+    /// The source address is taken from the `SSH_CONNECTION` environment
+    /// variable (the same place `run` derives `source_hostname` from); the
+    /// destination is the Mononoke endpoint we are relaying to. TLS metadata
+    /// TLVs can be layered on top of this once the server consumes them.
+    fn encode_proxy_header(&self) -> Result<Vec<u8>> {
+        let source = var("SSH_CONNECTION")
+            .ok()
+            .and_then(|line| {
+                let mut parts = line.split_whitespace();
+                let ip = parts.next()?.parse::<IpAddr>().ok()?;
+                let port = parts.next()?.parse::<u16>().ok()?;
+                Some(SocketAddr::new(ip, port))
             })
-            .compat()
-            .await
+            .ok_or_else(|| {
+                format_err!("cannot determine client address from SSH_CONNECTION for PROXY header")
+            })?;
+        let destination: SocketAddr = self.path.parse()?;
+
+        let addresses = match (source, destination) {
+            (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+                ProxyAddresses::Ipv4 { source, destination }
+            }
+            (SocketAddr::V6(source), SocketAddr::V6(destination)) => {
+                ProxyAddresses::Ipv6 { source, destination }
+            }
+            _ => bail!("client and Mononoke addresses must share an address family"),
+        };
+
+        let header = ProxyHeader::Version2 {
+            command: ProxyCommand::Proxy,
+            transport_protocol: ProxyTransportProtocol::Stream,
+            addresses,
+        };
+        let encoded = proxy_protocol::encode(header)
+            .map_err(|err| format_err!("failed to encode PROXY protocol header: {}", err))?;
+        Ok(encoded.to_vec())
     }
 
-    async fn internal_run(self, stdio: Stdio) -> Result<(), Error> {
+    async fn internal_run(
+        self,
+        stdio: Stdio,
+        scuba_logger: &mut ScubaSampleBuilder,
+    ) -> Result<(), Error> {
+        if self.transport == Transport::Quic {
+            return self.internal_run_quic(stdio, scuba_logger).await;
+        }
+
         let Stdio {
             preamble,
             stdin,
@@ -274,7 +601,20 @@ impl<'a> StdioRelay<'a> {
             stderr,
         } = stdio;
 
-        let socket = self.establish_connection().await?;
+        let socket = self.establish_connection(scuba_logger).await?;
+
+        // When acting as a remote proxy, announce the real client to Mononoke
+        // ahead of the ssh-framed stream. Disabled by default so servers that
+        // do not parse the header keep seeing the Preamble hostname.
+        let socket = if self.send_proxy_header {
+            let header = self.encode_proxy_header()?;
+            tokio_io::io::write_all(socket, header)
+                .map(|(socket, _buf)| socket)
+                .compat()
+                .await?
+        } else {
+            socket
+        };
 
         // Wrap the socket with the ssh codec
         let (socket_read, socket_write) = socket.split();
@@ -332,4 +672,83 @@ impl<'a> StdioRelay<'a> {
             .map_err(|(err, _)| err)
             .compat().await
     }
+
+    /// QUIC variant of [`Self::internal_run`]. Each logical ssh stream gets its
+    /// own QUIC stream, so stdout and stderr no longer block each other.
+    async fn internal_run_quic(
+        self,
+        stdio: Stdio,
+        scuba_logger: &mut ScubaSampleBuilder,
+    ) -> Result<(), Error> {
+        use futures::compat::{Sink01CompatExt, Stream01CompatExt};
+        use futures::{SinkExt, TryStreamExt};
+        use tokio_util::codec::{FramedRead, FramedWrite};
+
+        let Stdio {
+            preamble,
+            stdin,
+            stdout,
+            stderr,
+        } = stdio;
+
+        let addr: SocketAddr = self.path.parse()?;
+        let quic::QuicStreams {
+            send,
+            recv,
+            mut uni_streams,
+        } = quic::connect(
+            addr,
+            self.ssl_common_name,
+            self.cert,
+            self.private_key,
+            self.ca_pem,
+            self.insecure,
+            &self.alpn_protocols,
+        )
+        .await?;
+
+        scuba_logger.add("transport", "quic");
+
+        // Outbound: the Preamble then stdin, over the bidirectional stream.
+        let outbound = async move {
+            let mut tx = FramedWrite::new(send, SshEncoder::new());
+            tx.send(SshMsg::new(SshStream::Preamble(preamble), Bytes::new()))
+                .await?;
+            let mut stdin = stdin.compat().map_err(Error::from);
+            while let Some(buf) = stdin.try_next().await? {
+                tx.send(SshMsg::new(SshStream::Stdin, buf)).await?;
+            }
+            tx.close().await?;
+            Ok::<_, Error>(())
+        };
+
+        // stdout arrives on the bidirectional stream's read half.
+        let stdout_future = async move {
+            let mut rx = FramedRead::new(recv, SshDecoder::new());
+            let mut stdout = stdout.sink_compat().sink_map_err(Error::from);
+            while let Some(msg) = rx.try_next().await? {
+                if let SshStream::Stdout = msg.stream() {
+                    stdout.send(SshMsg::data(msg)).await?;
+                }
+            }
+            Ok::<_, Error>(())
+        };
+
+        // stderr arrives on the server-opened unidirectional stream. Accept it
+        // here, concurrently with `outbound`, because the server may only open
+        // it after reading the Preamble — accepting before the send would
+        // deadlock.
+        let stderr_future = async move {
+            let stderr_recv = quic::accept_stderr(&mut uni_streams).await?;
+            let mut rx = FramedRead::new(stderr_recv, SshDecoder::new());
+            let mut stderr = stderr.sink_compat().sink_map_err(Error::from);
+            while let Some(msg) = rx.try_next().await? {
+                stderr.send(SshMsg::data(msg)).await?;
+            }
+            Ok::<_, Error>(())
+        };
+
+        futures::try_join!(outbound, stdout_future, stderr_future)?;
+        Ok(())
+    }
 }
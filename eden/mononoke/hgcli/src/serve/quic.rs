@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! QUIC transport for the hgcli proxy.
+//!
+//! The default transport multiplexes stdout and stderr over a single
+//! TLS-over-TCP stream, so a stall on one logical stream blocks the other
+//! (head-of-line blocking). QUIC gives every logical ssh stream its own
+//! independent stream, and reconnects faster on lossy links. We map the three
+//! ssh streams onto:
+//!
+//!   * one bidirectional stream carrying the Preamble + stdin outbound and
+//!     stdout inbound, and
+//!   * one unidirectional stream, opened by the server, carrying stderr.
+//!
+//! The existing [`SshMsg`]/[`SshStream`] framing is preserved by running the
+//! same codecs over the QUIC stream handles.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{format_err, Error, Result};
+use quinn::{ClientConfig, Endpoint, IncomingUniStreams, NewConnection, RecvStream, SendStream};
+
+/// The QUIC stream handles backing a single hgcli session.
+pub struct QuicStreams {
+    /// Outbound half of the bidirectional stream (Preamble + stdin).
+    pub send: SendStream,
+    /// Inbound half of the bidirectional stream (stdout).
+    pub recv: RecvStream,
+    /// Incoming unidirectional streams. The caller accepts the server's stderr
+    /// stream off this handle concurrently with sending the Preamble, since the
+    /// server may only open stderr after it has read the Preamble — accepting
+    /// it here would deadlock.
+    pub uni_streams: IncomingUniStreams,
+}
+
+/// Build a rustls client config from the same PEM material used for the
+/// TLS-over-TCP transport.
+fn build_client_config(
+    cert: &str,
+    private_key: &str,
+    ca_pem: &str,
+    insecure: bool,
+    alpn_protocols: &[String],
+) -> Result<ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    let ca = fs::read(ca_pem)?;
+    for cert in rustls::internal::pemfile::certs(&mut &ca[..])
+        .map_err(|_| format_err!("failed to parse CA pem '{}'", ca_pem))?
+    {
+        roots
+            .add(&cert)
+            .map_err(|err| format_err!("failed to add CA certificate: {}", err))?;
+    }
+
+    let mut tls = rustls::ClientConfig::new();
+    tls.root_store = roots;
+
+    if insecure {
+        tls.dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+
+    // Present the client identity, mirroring set_certificate/set_private_key on
+    // the OpenSSL connector.
+    let client_certs = {
+        let pem = fs::read(cert)?;
+        rustls::internal::pemfile::certs(&mut &pem[..])
+            .map_err(|_| format_err!("failed to parse client cert '{}'", cert))?
+    };
+    let client_key = {
+        let pem = fs::read(private_key)?;
+        let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut &pem[..])
+            .map_err(|_| format_err!("failed to parse private key '{}'", private_key))?;
+        keys.pop()
+            .ok_or_else(|| format_err!("no private key found in '{}'", private_key))?
+    };
+    tls.set_single_client_cert(client_certs, client_key)
+        .map_err(|err| format_err!("failed to set client certificate: {}", err))?;
+
+    for protocol in alpn_protocols {
+        tls.alpn_protocols.push(protocol.as_bytes().to_vec());
+    }
+
+    Ok(ClientConfig {
+        crypto: Arc::new(tls),
+        transport: Arc::new(quinn::TransportConfig::default()),
+    })
+}
+
+/// Dial the Mononoke server at `addr` over QUIC, validating `server_name` the
+/// same way the TCP transport validates the SSL common name.
+pub async fn connect(
+    addr: SocketAddr,
+    server_name: &str,
+    cert: &str,
+    private_key: &str,
+    ca_pem: &str,
+    insecure: bool,
+    alpn_protocols: &[String],
+) -> Result<QuicStreams> {
+    let client_config =
+        build_client_config(cert, private_key, ca_pem, insecure, alpn_protocols)?;
+
+    let mut endpoint = Endpoint::builder();
+    endpoint.default_client_config(client_config);
+    let bind_addr: SocketAddr = if addr.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let (endpoint, _incoming) = endpoint.bind(&bind_addr)?;
+
+    let NewConnection {
+        connection,
+        uni_streams,
+        ..
+    } = endpoint
+        .connect(&addr, server_name)
+        .map_err(|err| format_err!("QUIC connect to {} failed: {}", addr, err))?
+        .await
+        .map_err(|err| format_err!("QUIC handshake with {} failed: {}", addr, err))?;
+
+    let (send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(|err| format_err!("opening QUIC bidirectional stream failed: {}", err))?;
+
+    Ok(QuicStreams {
+        send,
+        recv,
+        uni_streams,
+    })
+}
+
+/// Accept the server's unidirectional stderr stream. Called concurrently with
+/// the outbound Preamble/stdin send so the two don't deadlock.
+pub async fn accept_stderr(uni_streams: &mut IncomingUniStreams) -> Result<RecvStream> {
+    use futures::stream::StreamExt;
+    uni_streams
+        .next()
+        .await
+        .ok_or_else(|| format_err!("server did not open a QUIC stream for stderr"))?
+        .map_err(|err| format_err!("accepting QUIC stderr stream failed: {}", err))
+}
+
+/// Accepts any server certificate. Only used when `--insecure` is passed, to
+/// match the TLS transport's `SslVerifyMode::NONE` behaviour.
+struct NoCertificateVerification;
+
+impl rustls::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
@@ -26,14 +26,151 @@ use crate::facebook::rust_hooks::{
 use crate::{ChangesetHook, FileHook, HookManager};
 use anyhow::Error;
 use fbinit::FacebookInit;
-use metaconfig_types::RepoConfig;
+use metaconfig_types::{HookConfig, RepoConfig};
+use once_cell::sync::Lazy;
 use std::collections::HashSet;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
-enum LoadedRustHook {
+/// A loaded Rust hook, ready to be registered with the `HookManager`.
+pub enum LoadedRustHook {
     ChangesetHook(Box<dyn ChangesetHook>),
     FileHook(Box<dyn FileHook>),
 }
 
+/// Constructs a `LoadedRustHook` from the init token and the hook's config.
+/// Out-of-tree crates implement this (usually as a closure) to plug in custom
+/// hooks via [`register`] or [`HookRegistry::register`].
+pub type RustHookConstructor =
+    Box<dyn Fn(FacebookInit, &HookConfig) -> Result<LoadedRustHook, Error> + Send + Sync>;
+
+/// Maps a hook name to the constructor that builds it. Replaces the hardcoded
+/// `match hook_name` arm so hooks can be added without editing `load_hooks`.
+pub struct HookRegistry {
+    constructors: HashMap<String, RustHookConstructor>,
+}
+
+impl HookRegistry {
+    /// An empty registry.
+    pub fn new() -> Self {
+        HookRegistry {
+            constructors: HashMap::new(),
+        }
+    }
+
+    /// A registry pre-populated with every built-in Rust hook.
+    pub fn with_builtins() -> Self {
+        let mut registry = HookRegistry::new();
+        registry.register_builtins();
+        registry
+    }
+
+    /// Register a hook constructor under `name`, replacing any existing entry.
+    pub fn register<F>(&mut self, name: impl Into<String>, constructor: F)
+    where
+        F: Fn(FacebookInit, &HookConfig) -> Result<LoadedRustHook, Error> + Send + Sync + 'static,
+    {
+        self.constructors.insert(name.into(), Box::new(constructor));
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(name)
+    }
+
+    fn load(
+        &self,
+        fb: FacebookInit,
+        name: &str,
+        config: &HookConfig,
+    ) -> Result<LoadedRustHook, Error> {
+        let constructor = self
+            .constructors
+            .get(name)
+            .ok_or_else(|| ErrorKind::InvalidRustHook(name.to_string()))?;
+        constructor(fb, config)
+    }
+
+    fn register_builtins(&mut self) {
+        use LoadedRustHook::*;
+        self.register("always_fail_changeset", |_fb, _config| {
+            Ok(ChangesetHook(Box::new(AlwaysFailChangeset::new())))
+        });
+        self.register("block_cross_repo_commits", |_fb, _config| {
+            Ok(FileHook(Box::new(BlockCrossRepoCommits::new()?)))
+        });
+        self.register("block_empty_commit", |_fb, _config| {
+            Ok(ChangesetHook(Box::new(BlockEmptyCommit::new())))
+        });
+        self.register("check_nocommit", |_fb, config| {
+            Ok(FileHook(Box::new(CheckNocommitHook::new(config)?)))
+        });
+        self.register("check_unittests", |_fb, config| {
+            Ok(ChangesetHook(Box::new(CheckUnittestsHook::new(config)?)))
+        });
+        self.register("conflict_markers", |_fb, _config| {
+            Ok(FileHook(Box::new(ConflictMarkers::new())))
+        });
+        self.register("deny_files", |_fb, _config| {
+            Ok(FileHook(Box::new(DenyFiles::new()?)))
+        });
+        self.register("ensure_valid_email", |fb, config| {
+            Ok(ChangesetHook(Box::new(EnsureValidEmailHook::new(fb, config)?)))
+        });
+        self.register("gitattributes-textdirectives", |_fb, _config| {
+            Ok(FileHook(Box::new(GitattributesTextDirectives::new()?)))
+        });
+        self.register("limit_commit_message_length", |_fb, config| {
+            Ok(ChangesetHook(Box::new(LimitCommitMessageLength::new(config)?)))
+        });
+        self.register("limit_commitsize", |_fb, config| {
+            Ok(ChangesetHook(Box::new(LimitCommitsize::new(config))))
+        });
+        self.register("limit_filesize", |_fb, config| {
+            Ok(FileHook(Box::new(LimitFilesize::new(config))))
+        });
+        self.register("limit_path_length", |_fb, config| {
+            Ok(FileHook(Box::new(LimitPathLengthHook::new(config)?)))
+        });
+        self.register("no_bad_filenames", |_fb, _config| {
+            Ok(FileHook(Box::new(NoBadFilenames::new()?)))
+        });
+        self.register("no_insecure_filenames", |_fb, _config| {
+            Ok(FileHook(Box::new(NoInsecureFilenames::new()?)))
+        });
+        self.register("no_questionable_filenames", |_fb, _config| {
+            Ok(FileHook(Box::new(NoQuestionableFilenames::new()?)))
+        });
+        self.register("signed_source", |_fb, config| {
+            Ok(FileHook(Box::new(SignedSourceHook::new(config)?)))
+        });
+        self.register("tp2_symlinks_only", |_fb, _config| {
+            Ok(FileHook(Box::new(TP2SymlinksOnly::new()?)))
+        });
+        self.register("verify_integrity", |_fb, config| {
+            Ok(ChangesetHook(Box::new(VerifyIntegrityHook::new(config)?)))
+        });
+    }
+}
+
+impl Default for HookRegistry {
+    fn default() -> Self {
+        HookRegistry::with_builtins()
+    }
+}
+
+/// Hooks registered by out-of-tree crates, consulted by `load_hooks` on top of
+/// the built-in registry. Register before calling `load_hooks`.
+static EXTRA_HOOKS: Lazy<Mutex<HookRegistry>> = Lazy::new(|| Mutex::new(HookRegistry::new()));
+
+/// Register a custom hook constructor that `load_hooks` will honor, letting
+/// downstream repos ship hooks without patching the core loader.
+pub fn register<F>(name: impl Into<String>, constructor: F)
+where
+    F: Fn(FacebookInit, &HookConfig) -> Result<LoadedRustHook, Error> + Send + Sync + 'static,
+{
+    EXTRA_HOOKS.lock().expect("hook registry poisoned").register(name, constructor);
+}
+
 pub fn load_hooks(
     fb: FacebookInit,
     hook_manager: &mut HookManager,
@@ -42,6 +179,9 @@ pub fn load_hooks(
 ) -> Result<(), Error> {
     let mut hooks_not_disabled = disabled_hooks.clone();
 
+    let registry = HookRegistry::with_builtins();
+    let extra = EXTRA_HOOKS.lock().expect("hook registry poisoned");
+
     let mut hook_set = HashSet::new();
     for hook in config.hooks {
         use LoadedRustHook::*;
@@ -59,37 +199,20 @@ pub fn load_hooks(
             name.clone()
         };
 
-        let rust_hook = match hook_name.as_ref() {
-            "always_fail_changeset" => ChangesetHook(Box::new(AlwaysFailChangeset::new())),
-            "block_cross_repo_commits" => FileHook(Box::new(BlockCrossRepoCommits::new()?)),
-            "block_empty_commit" => ChangesetHook(Box::new(BlockEmptyCommit::new())),
-            "check_nocommit" => FileHook(Box::new(CheckNocommitHook::new(&hook.config)?)),
-            "check_unittests" => ChangesetHook(Box::new(CheckUnittestsHook::new(&hook.config)?)),
-            "conflict_markers" => FileHook(Box::new(ConflictMarkers::new())),
-            "deny_files" => FileHook(Box::new(DenyFiles::new()?)),
-            "ensure_valid_email" => {
-                ChangesetHook(Box::new(EnsureValidEmailHook::new(fb, &hook.config)?))
-            }
-            "gitattributes-textdirectives" => {
-                FileHook(Box::new(GitattributesTextDirectives::new()?))
-            }
-            "limit_commit_message_length" => {
-                ChangesetHook(Box::new(LimitCommitMessageLength::new(&hook.config)?))
-            }
-            "limit_commitsize" => ChangesetHook(Box::new(LimitCommitsize::new(&hook.config))),
-            "limit_filesize" => FileHook(Box::new(LimitFilesize::new(&hook.config))),
-            "limit_path_length" => FileHook(Box::new(LimitPathLengthHook::new(&hook.config)?)),
-            "no_bad_filenames" => FileHook(Box::new(NoBadFilenames::new()?)),
-            "no_insecure_filenames" => FileHook(Box::new(NoInsecureFilenames::new()?)),
-            "no_questionable_filenames" => FileHook(Box::new(NoQuestionableFilenames::new()?)),
-            "signed_source" => FileHook(Box::new(SignedSourceHook::new(&hook.config)?)),
-            "tp2_symlinks_only" => FileHook(Box::new(TP2SymlinksOnly::new()?)),
-            "verify_integrity" => ChangesetHook(Box::new(VerifyIntegrityHook::new(&hook.config)?)),
-            "verify_reviewedby_info" => ChangesetHook(Box::new(VerifyReviewedbyInfo::new(
+        // `verify_reviewedby_info` needs the reviewers ACL checker owned by the
+        // HookManager, which a generic constructor has no access to, so it stays
+        // wired up here; every other hook is resolved through the registry.
+        let rust_hook = if hook_name == "verify_reviewedby_info" {
+            ChangesetHook(Box::new(VerifyReviewedbyInfo::new(
                 &hook.config,
                 hook_manager.get_reviewers_acl_checker(),
-            )?)),
-            _ => return Err(ErrorKind::InvalidRustHook(name.clone()).into()),
+            )?))
+        } else if extra.contains(&hook_name) {
+            extra.load(fb, &hook_name, &hook.config)?
+        } else if registry.contains(&hook_name) {
+            registry.load(fb, &hook_name, &hook.config)?
+        } else {
+            return Err(ErrorKind::InvalidRustHook(name.clone()).into());
         };
 
         match rust_hook {
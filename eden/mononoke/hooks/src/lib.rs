@@ -0,0 +1,13 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Hook management and execution for Mononoke.
+
+pub mod hook_loader;
+pub mod tailer;
+
+pub use tailer::{HookResults, Tailer};
@@ -0,0 +1,217 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A dry-run harness that replays the hooks loaded for a bookmark over
+//! historical changesets, collecting what *would* fire without rejecting or
+//! mutating anything. This lets operators backtest a new hook against real
+//! history before enabling it on a bookmark.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use blobrepo::BlobRepo;
+use bookmarks::BookmarkName;
+use context::CoreContext;
+use futures::{
+    future,
+    future::TryFutureExt,
+    stream::{Stream, StreamExt, TryStreamExt},
+};
+use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
+
+use crate::{
+    ChangesetHookExecutionId, FileHookExecutionId, HookExecution, HookManager,
+};
+
+/// The hooks that would have run for a single changeset, along with their
+/// (non-binding) outcomes.
+pub struct HookResults {
+    pub cs_id: ChangesetId,
+    pub hg_cs_id: HgChangesetId,
+    pub file_hooks_results: Vec<(FileHookExecutionId, HookExecution)>,
+    pub cs_hooks_result: Vec<(ChangesetHookExecutionId, HookExecution)>,
+}
+
+impl HookResults {
+    /// Human-readable descriptions of every rejection across the file and
+    /// changeset hooks, suitable for surfacing to an operator.
+    pub fn rejections(&self) -> Vec<String> {
+        let files = self
+            .file_hooks_results
+            .iter()
+            .filter_map(|(id, exec)| rejection(&id.hook_name, exec));
+        let changesets = self
+            .cs_hooks_result
+            .iter()
+            .filter_map(|(id, exec)| rejection(&id.hook_name, exec));
+        files.chain(changesets).collect()
+    }
+}
+
+fn rejection(hook_name: &str, execution: &HookExecution) -> Option<String> {
+    match execution {
+        HookExecution::Accepted => None,
+        HookExecution::Rejected(info) => Some(format!("{}: {}", hook_name, info.description)),
+    }
+}
+
+/// Replays the hooks registered for a bookmark over a range of changesets.
+pub struct Tailer {
+    ctx: CoreContext,
+    repo: BlobRepo,
+    hook_manager: Arc<HookManager>,
+    bookmark: BookmarkName,
+    /// If set, limits the replay to these hook names (intersected with the
+    /// hooks `set_hooks_for_bookmark` actually registered for the bookmark).
+    only_hooks: Option<HashSet<String>>,
+}
+
+impl Tailer {
+    pub fn new(
+        ctx: CoreContext,
+        repo: BlobRepo,
+        hook_manager: Arc<HookManager>,
+        bookmark: BookmarkName,
+        only_hooks: Option<HashSet<String>>,
+    ) -> Self {
+        Tailer {
+            ctx,
+            repo,
+            hook_manager,
+            bookmark,
+            only_hooks,
+        }
+    }
+
+    /// The hooks this tailer will exercise: those registered for the bookmark,
+    /// narrowed to `only_hooks` when that filter is set.
+    fn hooks_to_run(&self) -> HashSet<String> {
+        let registered: HashSet<String> = self
+            .hook_manager
+            .hooks_for_bookmark(&self.bookmark)
+            .into_iter()
+            .collect();
+        match &self.only_hooks {
+            Some(only) => registered.intersection(only).cloned().collect(),
+            None => registered,
+        }
+    }
+
+    /// Replay the hooks over the most recent `count` changesets reachable from
+    /// the bookmark, yielding one `HookResults` per changeset. Nothing is
+    /// rejected or mutated — the results are advisory.
+    pub fn run_with_limit(
+        &self,
+        count: u64,
+    ) -> impl Stream<Item = Result<HookResults>> + '_ {
+        let hooks = self.hooks_to_run();
+
+        self.repo
+            .get_bonsai_bookmark(self.ctx.clone(), &self.bookmark)
+            .map_ok(move |maybe_cs_id| maybe_cs_id.into_iter().collect::<Vec<_>>())
+            .map_ok(move |cs_ids| futures::stream::iter(cs_ids).map(Ok))
+            .try_flatten_stream()
+            .and_then(move |cs_id| self.ancestors(cs_id, count))
+            .try_flatten()
+            .and_then(move |cs_id| {
+                let hooks = hooks.clone();
+                self.run_hooks(cs_id, hooks)
+            })
+    }
+
+    /// Resolve the bonsai changeset to its hg counterpart and run both the file
+    /// and changeset hooks concurrently for throughput.
+    async fn run_hooks(
+        &self,
+        cs_id: ChangesetId,
+        hooks: HashSet<String>,
+    ) -> Result<HookResults> {
+        let hg_cs_id = self
+            .repo
+            .get_hg_from_bonsai_changeset(self.ctx.clone(), cs_id)
+            .await?;
+
+        let file_hooks = self.hook_manager.run_file_hooks_for_bookmark(
+            &self.ctx,
+            hg_cs_id,
+            &self.bookmark,
+            None,
+            &hooks,
+        );
+        let cs_hooks = self.hook_manager.run_changeset_hooks_for_bookmark(
+            &self.ctx,
+            hg_cs_id,
+            &self.bookmark,
+            None,
+            &hooks,
+        );
+
+        let (file_hooks_results, cs_hooks_result) = future::try_join(file_hooks, cs_hooks).await?;
+
+        Ok(HookResults {
+            cs_id,
+            hg_cs_id,
+            file_hooks_results,
+            cs_hooks_result,
+        })
+    }
+
+    /// The first `count` ancestors of `cs_id` in the bookmark's history,
+    /// newest first.
+    async fn ancestors(
+        &self,
+        cs_id: ChangesetId,
+        count: u64,
+    ) -> Result<impl Stream<Item = Result<ChangesetId>>> {
+        let ancestors = self
+            .repo
+            .get_changeset_ancestors(self.ctx.clone(), cs_id)
+            .take(count as usize);
+        Ok(ancestors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::HookRejectionInfo;
+
+    #[test]
+    fn accepted_execution_is_not_a_rejection() {
+        assert_eq!(rejection("my_hook", &HookExecution::Accepted), None);
+    }
+
+    #[test]
+    fn rejected_execution_reports_hook_name_and_description() {
+        let execution =
+            HookExecution::Rejected(HookRejectionInfo::new("too long", "file is too long".into()));
+        assert_eq!(
+            rejection("limit_filesize", &execution),
+            Some("limit_filesize: too long".to_string())
+        );
+    }
+
+    #[test]
+    fn chained_rejections_skip_accepted_hooks() {
+        // Chain per-hook `rejection` results the way `HookResults::rejections`
+        // does, over one rejected file hook and one accepted changeset hook:
+        // only the rejection is reported.
+        let file_exec = HookExecution::Rejected(HookRejectionInfo::new("bad path", "nope".into()));
+        let files = std::iter::once(("deny_files", &file_exec))
+            .filter_map(|(name, exec)| rejection(name, exec));
+        let cs_exec = HookExecution::Accepted;
+        let changesets =
+            std::iter::once(("block_empty_commit", &cs_exec)).filter_map(|(name, exec)| {
+                rejection(name, exec)
+            });
+
+        let collected: Vec<String> = files.chain(changesets).collect();
+        assert_eq!(collected, vec!["deny_files: bad path".to_string()]);
+    }
+}
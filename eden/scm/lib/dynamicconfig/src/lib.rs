@@ -10,7 +10,7 @@ use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fs;
 use std::hash::{Hash, Hasher};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 #[cfg(not(feature = "fb"))]
@@ -18,10 +18,13 @@ use anyhow::Error;
 use anyhow::{anyhow, bail, Result};
 use hostname;
 use minibytes::Text;
+use regex::Regex;
 
 use configparser::config::ConfigSet;
 use hgtime::HgTime;
 
+const DYNAMIC_SOURCE: &str = "dynamicconfigs";
+
 #[cfg(feature = "fb")]
 mod fb;
 
@@ -225,18 +228,132 @@ impl Generator {
         &mut self,
         value: impl Into<Text> + Clone + std::fmt::Display,
     ) -> Result<()> {
-        let value_copy = value.clone();
-        let errors = self.config.parse(value, &"dynamicconfigs".into());
-        if !errors.is_empty() {
-            bail!(
-                "invalid dynamic config blob: '{}'\nerrors: '{:?}'",
-                value_copy,
-                errors
-            );
+        let blob: Text = value.into();
+        let mut seen = HashSet::new();
+        self.parse_hgrc(blob.as_ref(), None, &mut seen)
+    }
+
+    /// Parse a single hgrc blob into `self.config` under the `dynamicconfigs`
+    /// source, understanding the Mercurial directive set (`%unset`,
+    /// `%include`) and continuation lines so that rules can layer on top of
+    /// each other instead of only ever adding keys.
+    ///
+    /// `base` is the directory the blob was read from, used to resolve relative
+    /// `%include` paths; `seen` tracks already-included files so include cycles
+    /// are rejected rather than recursed into forever.
+    fn parse_hgrc(
+        &mut self,
+        blob: &str,
+        base: Option<&Path>,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<()> {
+        // Only treat a line as a directive when the keyword is a whole word, so
+        // a key literally named e.g. `%unsettled` is not mistaken for `%unset`.
+        fn strip_directive<'a>(trimmed: &'a str, directive: &str) -> Option<&'a str> {
+            let rest = trimmed.strip_prefix(directive)?;
+            if rest.is_empty() || rest.starts_with(|c: char| c.is_whitespace()) {
+                Some(rest)
+            } else {
+                None
+            }
+        }
+
+        let section_re = Regex::new(r"^\[([^\[]+)\]").unwrap();
+        let item_re = Regex::new(r"^([^=\s][^=]*?)\s*=\s*(.*\S)?").unwrap();
+        let source: Text = DYNAMIC_SOURCE.into();
+
+        let mut section = String::new();
+        // The key and the value we last wrote into the dynamic source. The
+        // value is tracked here rather than read back via `get` so a
+        // continuation line extends what this blob set, not whatever a
+        // higher-priority source happens to resolve for the same key.
+        let mut last_key: Option<String> = None;
+        let mut last_value: String = String::new();
+
+        for line in blob.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+                continue;
+            }
+
+            // A line that starts with whitespace followed by non-whitespace is a
+            // continuation of the previous key's value.
+            if line.starts_with(|c: char| c.is_whitespace()) {
+                if let Some(key) = &last_key {
+                    last_value = format!("{}\n{}", last_value, trimmed);
+                    self.config.set(&section, key, Some(last_value.clone()), &source);
+                }
+                continue;
+            }
+
+            if let Some(name) = strip_directive(trimmed, "%unset") {
+                // `%unset` of a never-set key is a no-op; setting the value to
+                // `None` removes it from the accumulating config.
+                let name = name.trim();
+                if !name.is_empty() {
+                    self.config
+                        .set(&section, name, Option::<Text>::None, &source);
+                }
+                last_key = None;
+                last_value.clear();
+                continue;
+            }
+
+            if let Some(path) = strip_directive(trimmed, "%include") {
+                let path = path.trim();
+                if !path.is_empty() {
+                    self.include_hgrc(path, base, seen)?;
+                }
+                last_key = None;
+                last_value.clear();
+                continue;
+            }
+
+            if let Some(caps) = section_re.captures(line) {
+                section = caps[1].trim().to_string();
+                last_key = None;
+                last_value.clear();
+                continue;
+            }
+
+            if let Some(caps) = item_re.captures(line) {
+                let key = caps[1].to_string();
+                let value = caps.get(2).map(|m| m.as_str().to_string()).unwrap_or_default();
+                self.config.set(&section, &key, Some(value.clone()), &source);
+                last_key = Some(key);
+                last_value = value;
+                continue;
+            }
+
+            bail!("invalid dynamic config line: '{}'", line);
         }
+
         Ok(())
     }
 
+    /// Splice in another hgrc blob referenced by `%include`, resolving `path`
+    /// relative to the including blob's directory and guarding against cycles.
+    fn include_hgrc(
+        &mut self,
+        path: &str,
+        base: Option<&Path>,
+        seen: &mut HashSet<PathBuf>,
+    ) -> Result<()> {
+        let target = match base {
+            Some(base) if !Path::new(path).is_absolute() => base.join(path),
+            _ => PathBuf::from(path),
+        };
+        let canonical = fs::canonicalize(&target).unwrap_or_else(|_| target.clone());
+        if !seen.insert(canonical.clone()) {
+            bail!("%include cycle detected while loading '{}'", target.display());
+        }
+        let blob = fs::read_to_string(&target)
+            .map_err(|err| anyhow!("failed to %include '{}': {}", target.display(), err))?;
+        let result = self.parse_hgrc(&blob, canonical.parent(), seen);
+        seen.remove(&canonical);
+        result
+    }
+
     pub fn execute(mut self) -> Result<ConfigSet> {
         if std::env::var("HG_TEST_DYNAMICCONFIG").is_ok() {
             self._execute(test_rules)?;
@@ -366,4 +483,39 @@ key=value
 "
         );
     }
+
+    #[test]
+    fn test_load_hgrc_directives() {
+        let mut generator = Generator::new("test_repo".to_string()).unwrap();
+
+        // An earlier rule turns an experiment on...
+        generator
+            .load_hgrc(
+                "[experiment]
+feature=on
+note=first",
+            )
+            .unwrap();
+
+        // ...and a later rule retracts it and appends a continuation.
+        generator
+            .load_hgrc(
+                "[experiment]
+%unset feature
+note=second
+  and third
+; this is a comment
+# so is this",
+            )
+            .unwrap();
+
+        assert_eq!(generator.config.get("experiment", "feature"), None);
+        assert_eq!(
+            generator
+                .config
+                .get("experiment", "note")
+                .map(|v| v.to_string()),
+            Some("second\nand third".to_string())
+        );
+    }
 }